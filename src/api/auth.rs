@@ -0,0 +1,102 @@
+use crate::api::errors::errors::unauthorized;
+use crate::db::db::Database;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use warp::Filter;
+
+/// Rejection emitted when a request's `X-Signature` header is missing, malformed, or does not
+/// verify against the sender's registered public key
+#[derive(Debug)]
+struct Unauthorized(String);
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Extracts the `X-Signature` header (hex encoded) and the raw request body, verifies the
+/// signature against the public key registered for the `user_id` embedded in the body, and
+/// yields the parsed body to the handler
+///
+/// The signed message is the exact bytes of the request body, so clients must sign the JSON
+/// they send verbatim.
+pub fn verify_signed_body<T>(
+    database: impl warp::Filter<Extract = (Arc<Database>,), Error = warp::Rejection> + Clone,
+) -> impl warp::Filter<Extract = (T,), Error = warp::Rejection> + Clone
+where
+    T: DeserializeOwned + HasSignedFields + Send + 'static,
+{
+    warp::header::optional::<String>("X-Signature")
+        .and(warp::body::bytes())
+        .and(database)
+        .and_then(|signature_hex: Option<String>, body: bytes::Bytes, db: Arc<Database>| async move {
+            let Some(signature_hex) = signature_hex else {
+                return Err(warp::reject::custom(Unauthorized("missing signature".to_string())));
+            };
+            verify_and_parse::<T>(signature_hex, body, db).await
+        })
+}
+
+async fn verify_and_parse<T>(
+    signature_hex: String,
+    body: bytes::Bytes,
+    db: Arc<Database>,
+) -> Result<T, warp::Rejection>
+where
+    T: DeserializeOwned + HasSignedFields,
+{
+    let request: T = serde_json::from_slice(&body)
+        .map_err(|e| warp::reject::custom(Unauthorized(e.to_string())))?;
+    let signature_bytes = hex::decode(&signature_hex)
+        .map_err(|_| warp::reject::custom(Unauthorized("invalid signature encoding".to_string())))?;
+    let signature = Signature::from_slice(&signature_bytes)
+        .map_err(|_| warp::reject::custom(Unauthorized("invalid signature length".to_string())))?;
+    // An unregistered user_id, a corrupt stored public key, and a bad signature all map to the
+    // same generic message below, so a client can't enumerate registered user_ids by diffing 401
+    // bodies across different ids
+    let bad_signature = || warp::reject::custom(Unauthorized("signature verification failed".to_string()));
+    let public_key_bytes = db
+        .get_public_key(request.user_id())
+        .await
+        .map_err(|e| {
+            // An unregistered user_id (RowNotFound) is routine and expected here, but any other
+            // error means the database itself is unhealthy; only log that case so a real outage
+            // isn't buried under normal 401 traffic
+            if !matches!(e.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound)) {
+                eprintln!("get_public_key failed during signature verification: {e}");
+            }
+            bad_signature()
+        })?;
+    let public_key_bytes: [u8; 32] = public_key_bytes
+        .try_into()
+        .map_err(|_| {
+            eprintln!("stored public key for user_id {} is not 32 bytes", request.user_id());
+            bad_signature()
+        })?;
+    let verifying_key = VerifyingKey::from_bytes(&public_key_bytes)
+        .map_err(|_| bad_signature())?;
+    verifying_key
+        .verify_strict(&body, &signature)
+        .map_err(|_| bad_signature())?;
+    db.check_and_consume_nonce(request.user_id(), request.nonce())
+        .await
+        .map_err(|e| warp::reject::custom(Unauthorized(e.to_string())))?;
+    Ok(request)
+}
+
+/// Implemented by request bodies that must be authenticated via [`verify_signed_body`]
+pub trait HasSignedFields {
+    /// The user the request claims to act as, used to look up the verifying key
+    fn user_id(&self) -> u32;
+    /// The per-request nonce folded into the signed payload, used to reject replays
+    fn nonce(&self) -> u64;
+}
+
+/// Converts an [`Unauthorized`] rejection into a 401 JSON error response, leaving all other
+/// rejections untouched so warp's default handling still applies
+pub async fn handle_unauthorized(
+    err: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(Unauthorized(message)) = err.find() {
+        return Ok(unauthorized(message.clone()));
+    }
+    Err(err)
+}
@@ -46,6 +46,11 @@ fn error_message(message: String, status: u16) -> warp::reply::Response {
         .into_response()
 }
 
+#[inline]
+pub fn unauthorized(message: String) -> warp::reply::Response {
+    error_message(message, 401)
+}
+
 #[inline]
 pub fn empty_json() -> warp::reply::Response {
     warp::http::Response::builder()
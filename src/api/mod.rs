@@ -0,0 +1,3 @@
+pub mod routes;
+pub mod errors;
+pub mod auth;
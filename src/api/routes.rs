@@ -3,22 +3,77 @@ use warp::{Filter, Reply};
 use crate::db::db::Database;
 use std::sync::Arc;
 use crate::check_error;
+use crate::api::auth::{verify_signed_body, handle_unauthorized, HasSignedFields};
 use crate::api::errors::errors::from_error;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 /// Max request size in bytes
 const MAX_REQUEST_SIZE: u64 = 1024;
 
+/// Maximum number of rows `get_history` returns in one call, regardless of the requested `limit`
+const MAX_HISTORY_LIMIT: u32 = 1000;
+
 #[derive(Deserialize)]
 struct UserQuery {
     user_id: u32,
 }
 
+#[derive(Deserialize)]
+struct HistoryQuery {
+    user_id: u32,
+    #[serde(default)]
+    since: u64,
+    limit: Option<u32>,
+    /// If set and no rows are newer than `since`, park for at most this many milliseconds
+    /// waiting for the next committed mutation before returning an empty array
+    long_poll_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RegisterQuery {
+    user_id: u32,
+    /// Hex encoded 32 byte ed25519 public key for the new user
+    public_key: String,
+}
+
 #[derive(Deserialize)]
 struct ChangeBalanceRequest {
     sign: String,
     amount: i64,
     user_id: u32,
+    /// Strictly increasing per-user value folded into the signed payload to reject replays
+    nonce: u64,
+}
+
+impl HasSignedFields for ChangeBalanceRequest {
+    fn user_id(&self) -> u32 {
+        self.user_id
+    }
+
+    fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    sign: String,
+    amount: i64,
+    user_id: u32,
+    to_user_id: u32,
+    /// Strictly increasing per-user value folded into the signed payload to reject replays
+    nonce: u64,
+}
+
+impl HasSignedFields for TransferRequest {
+    fn user_id(&self) -> u32 {
+        self.user_id
+    }
+
+    fn nonce(&self) -> u64 {
+        self.nonce
+    }
 }
 
 pub async fn run_server(listen_address: &str, database: Database) {
@@ -27,7 +82,7 @@ pub async fn run_server(listen_address: &str, database: Database) {
     let register = warp::put()
         .and(warp::path("register"))
         .and(warp::path::end())
-        .and(warp::query::<UserQuery>())
+        .and(warp::query::<RegisterQuery>())
         .and(database.clone())
         .and_then(register);
     let users_path = warp::path("users");
@@ -36,9 +91,15 @@ pub async fn run_server(listen_address: &str, database: Database) {
         .and(warp::query::<UserQuery>())
         .and(database.clone())
         .and_then(get_free_balance));
-    // Limit body
+    let get_history = users_path.and(warp::get()
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and(database.clone())
+        .and_then(get_history));
+    // Limit body, then require a valid signature over the raw body before parsing it
     let body_limiter = warp::body::content_length_limit(MAX_REQUEST_SIZE)
-        .and(warp::body::json::<ChangeBalanceRequest>())
+        .and(verify_signed_body::<ChangeBalanceRequest>(database.clone()))
         .and(database.clone());
     let add_free_balance = users_path.and(warp::post()
         .and(warp::path("free"))
@@ -70,12 +131,37 @@ pub async fn run_server(listen_address: &str, database: Database) {
         .and(warp::path::end())
         .and(body_limiter.clone())
         .and_then(withdraw_blocked_balance));
+    let transfer_body_limiter = warp::body::content_length_limit(MAX_REQUEST_SIZE)
+        .and(verify_signed_body::<TransferRequest>(database.clone()))
+        .and(database.clone());
+    let transfer = users_path.and(warp::post()
+        .and(warp::path("transfer"))
+        .and(warp::path::end())
+        .and(transfer_body_limiter)
+        .and_then(transfer));
     let final_routes = add_free_balance.or(register)
-        .or(get_free_balance).or(withdraw_free_balance).or(block_free_balance)
-        .or(unblock_blocked_balance).or(withdraw_blocked_balance);
-    warp::serve(final_routes)
-        .run(listen_address.parse::<SocketAddr>().expect("invalid listen address"))
-        .await;
+        .or(get_free_balance).or(get_history).or(withdraw_free_balance).or(block_free_balance)
+        .or(unblock_blocked_balance).or(withdraw_blocked_balance).or(transfer)
+        .recover(handle_unauthorized);
+    let addr = listen_address.parse::<SocketAddr>().expect("invalid listen address");
+    let (_, server) = warp::serve(final_routes).bind_with_graceful_shutdown(addr, shutdown_signal());
+    // The socket is bound as soon as bind_with_graceful_shutdown returns, so the service is now
+    // ready to accept requests
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+    server.await;
+}
+
+/// Resolves once SIGTERM or SIGINT is received, letting in-flight balance transactions (and
+/// their `lock_map` guards) complete before `run_server` returns, rather than the process being
+/// killed mid-transaction
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
 }
 
 /// Tries to register a new user in database
@@ -88,8 +174,12 @@ pub async fn run_server(listen_address: &str, database: Database) {
 /// returns: Result<Response<Body>, Rejection> This function always accepts the request
 /// However, it fails with error body when the user already exists in database
 ///
-async fn register(user: UserQuery, db: Arc<Database>) -> Result<warp::reply::Response, warp::Rejection> {
-    let result = db.register_user(user.user_id).await;
+async fn register(user: RegisterQuery, db: Arc<Database>) -> Result<warp::reply::Response, warp::Rejection> {
+    let public_key = match hex::decode(&user.public_key) {
+        Ok(key) => key,
+        Err(e) => return Ok(from_error(anyhow::Error::msg(e.to_string()))),
+    };
+    let result = db.register_user(user.user_id, &public_key).await;
     check_error!(result)
 }
 
@@ -98,6 +188,40 @@ async fn get_free_balance(user: UserQuery, db: Arc<Database>) -> Result<warp::re
     check_error!(result)
 }
 
+async fn get_history(query: HistoryQuery, db: Arc<Database>) -> Result<warp::reply::Response, warp::Rejection> {
+    let limit = query.limit.unwrap_or(MAX_HISTORY_LIMIT).min(MAX_HISTORY_LIMIT);
+    // Only subscribe when the caller actually wants to long-poll: this endpoint is
+    // unauthenticated, so unconditionally calling get_notify would let anyone grow notify_map
+    // without bound just by passing arbitrary user_ids. Subscribe before the first read so a
+    // mutation committed between that read and the wait below is never missed.
+    let Some(long_poll_ms) = query.long_poll_ms else {
+        let result = db.get_history(query.user_id, query.since, limit).await;
+        check_error!(result);
+    };
+    let notify = db.get_notify(query.user_id).await;
+    let notified = notify.notified();
+    let rows = match db.get_history(query.user_id, query.since, limit).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            drop(notified);
+            drop(notify);
+            db.release_notify(query.user_id).await;
+            return Ok(from_error(e));
+        }
+    };
+    if !rows.is_empty() {
+        drop(notified);
+        drop(notify);
+        db.release_notify(query.user_id).await;
+        return Ok(warp::reply::json(&rows).into_response());
+    }
+    let _ = tokio::time::timeout(Duration::from_millis(long_poll_ms), notified).await;
+    drop(notify);
+    db.release_notify(query.user_id).await;
+    let result = db.get_history(query.user_id, query.since, limit).await;
+    check_error!(result);
+}
+
 async fn add_free_balance(
     request: ChangeBalanceRequest,
     db: Arc<Database>,
@@ -137,3 +261,11 @@ async fn withdraw_blocked_balance(
     let result = db.withdraw_blocked_balance(request.user_id, request.sign, -request.amount).await;
     check_error!(result)
 }
+
+async fn transfer(
+    request: TransferRequest,
+    db: Arc<Database>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let result = db.transfer(request.user_id, request.to_user_id, request.sign, request.amount).await;
+    check_error!(result)
+}
@@ -2,6 +2,20 @@ use proc_macro::*;
 use quote::quote;
 use syn::PatIdent;
 
+/// Wraps an `async fn(&self, ..., id: UserIdType, ...)` so that the whole body runs while
+/// holding a per-`id` lock taken from `self.lock_map`, and evicts that `id`'s entry from the map
+/// once the body finishes via `Database::evict_if_unused`.
+///
+/// Eviction is only safe once `Arc::strong_count` on the entry shows that the map itself holds
+/// the last reference: if another task is parked waiting on the same user's mutex, it is still
+/// holding a clone of that `Arc` and the count will be greater than one, so the entry is left in
+/// place rather than risking that waiter losing its lock. This keeps `lock_map` from growing by
+/// one entry per distinct `user_id` forever while never evicting out from under a concurrent
+/// request for the same user. `Database::transfer` and `notify_change` share the same helper for
+/// their own two-id and notifier bookkeeping respectively.
+///
+/// The body is wrapped in an `async move` block so the cleanup below runs unconditionally, even
+/// when the original body returns early.
 #[proc_macro_attribute]
 pub fn locker(args: TokenStream, input: TokenStream) -> TokenStream {
     let variable_name = args.to_string();
@@ -35,19 +49,33 @@ pub fn locker(args: TokenStream, input: TokenStream) -> TokenStream {
     if id.is_none() {
         panic!("one of the arguments must be \"{}\"", variable_name)
     }
-    // Now insert the data
+    // Wrap the original body in its own async block so any early `return` inside it only exits
+    // the body, letting the lock release and map eviction below always run afterward
     let id_indent = id.unwrap().ident;
-    fn_item.block.stmts.insert(0, syn::parse(quote!(let lock_guard = lock_guard.lock().await;).into()).unwrap());
-    fn_item.block.stmts.insert(0, syn::parse(quote!(drop(map);).into()).unwrap());
-    fn_item.block.stmts.insert(0, syn::parse(quote! {let lock_guard = map.entry(#id_indent).or_default().clone();}.into()).unwrap());
-    fn_item.block.stmts.insert(0, syn::parse(quote!(let mut map = self.lock_map.lock().await;).into()).unwrap());
-    let drop_statement = syn::parse(quote!(drop(lock_guard);).into()).unwrap();
-    if fn_item.sig.output == syn::ReturnType::Default { // if there is no return, add the drop to last line
-        fn_item.block.stmts.push(drop_statement);
-    } else { // otherwise, add it to last line before it
-        fn_item.block.stmts.insert(fn_item.block.stmts.len() - 1, drop_statement);
+    let original_block = syn::Block {
+        brace_token: Default::default(),
+        stmts: fn_item.block.stmts.clone(),
+    };
+    let mut stmts: Vec<syn::Stmt> = Vec::new();
+    stmts.push(syn::parse(quote!(let mut map = self.lock_map.lock().await;).into()).unwrap());
+    stmts.push(syn::parse(quote! {let lock_arc = map.entry(#id_indent).or_default().clone();}.into()).unwrap());
+    stmts.push(syn::parse(quote!(drop(map);).into()).unwrap());
+    stmts.push(syn::parse(quote!(let lock_guard = lock_arc.lock().await;).into()).unwrap());
+    stmts.push(syn::parse(quote! {let _locker_result = async move #original_block.await;}.into()).unwrap());
+    // Drop the guard, then the Arc clone itself (shadowing `lock_arc` with the guard would leave
+    // the original Arc alive until the end of the function, holding strong_count at 2 forever and
+    // making the eviction check below never fire), before checking whether the map holds the last
+    // reference
+    stmts.push(syn::parse(quote!(drop(lock_guard);).into()).unwrap());
+    stmts.push(syn::parse(quote!(drop(lock_arc);).into()).unwrap());
+    stmts.push(syn::parse(quote! {
+        Database::evict_if_unused(&self.lock_map, #id_indent).await;
+    }.into()).unwrap());
+    if fn_item.sig.output != syn::ReturnType::Default {
+        stmts.push(syn::parse(quote!(return _locker_result;).into()).unwrap());
     }
+    fn_item.block.stmts = stmts;
     // Return the function
     use quote::ToTokens;
     item.into_token_stream().into()
-}
\ No newline at end of file
+}